@@ -1,8 +1,5 @@
-use anyhow::{anyhow, Result};
-use std::fs::File;
+use anyhow::Result;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
 
 /// Attempt to convert a file to plain text:
 /// - If extension is .txt, just open it.
@@ -10,7 +7,16 @@ use std::process::{Command, Stdio};
 /// - If .docx, call `pandoc -f docx -t plain file.docx` => stdout.
 ///
 /// Returns a `Box<dyn BufRead>` that streams text lines.
-pub fn convert_to_text(path: &PathBuf) -> Result<Box<dyn BufRead>> {
+///
+/// Only available natively: it shells out to `pdftotext`/`pandoc`, and wasm32
+/// (running in a browser) has neither a filesystem path to open nor a
+/// subprocess to spawn. See the wasm32 `convert_to_text` below for that target.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn convert_to_text(path: &std::path::PathBuf) -> Result<Box<dyn BufRead>> {
+    use anyhow::anyhow;
+    use std::fs::File;
+    use std::process::{Command, Stdio};
+
     let ext = path
         .extension()
         .unwrap_or_default()
@@ -48,4 +54,14 @@ pub fn convert_to_text(path: &PathBuf) -> Result<Box<dyn BufRead>> {
             Ok(Box::new(reader))
         }
     }
+}
+
+/// wasm32 equivalent of the native `convert_to_text`: there's no filesystem
+/// path or `pdftotext`/`pandoc` subprocess to call into from a browser, so
+/// callers (e.g. a `<input type=file>` read via `FileReader`) hand us the
+/// already-decoded bytes directly. PDF/DOCX extraction isn't available on
+/// this target; pass already-converted plain text instead.
+#[cfg(target_arch = "wasm32")]
+pub fn convert_to_text(bytes: &[u8]) -> Result<Box<dyn BufRead>> {
+    Ok(Box::new(BufReader::new(std::io::Cursor::new(bytes.to_vec()))))
 }
\ No newline at end of file
@@ -1,9 +1,22 @@
 use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use wgpu::util::DeviceExt;
 
 pub struct GpuContext {
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
+    pub buffer_pool: Mutex<BufferPool>,
+    pub staging_belt: Mutex<StagingBelt>,
+    /// Compiled pipelines keyed by (shader source pointer, entry point), so
+    /// `dispatch_compute` parses and compiles a given kernel once instead of
+    /// on every call — the hot loop in `InfiniAttentionGpu::forward` calls it
+    /// once per head per segment.
+    pipeline_cache: Mutex<HashMap<(usize, &'static str), Arc<wgpu::ComputePipeline>>>,
+    // Keeps the background poller alive for the lifetime of the context;
+    // never read, just dropped (and joined) alongside everything else.
+    #[cfg(not(target_arch = "wasm32"))]
+    _device_poller: DevicePoller,
 }
 
 impl GpuContext {
@@ -29,14 +42,226 @@ impl GpuContext {
                 None,
             )
             .await?;
-        Ok(Self { device, queue })
+
+        #[cfg(not(target_arch = "wasm32"))]
+        let _device_poller = DevicePoller::spawn(device.clone());
+
+        Ok(Self {
+            device,
+            queue,
+            buffer_pool: Mutex::new(BufferPool::new()),
+            staging_belt: Mutex::new(StagingBelt::new(STAGING_BELT_CHUNK_SIZE)),
+            pipeline_cache: Mutex::new(HashMap::new()),
+            #[cfg(not(target_arch = "wasm32"))]
+            _device_poller,
+        })
     }
 }
 
-// ----------------------------------------------------------------
-// The rest of this file remains the same. 
-// Just ensure bytemuck references compile now that it's included.
-// ----------------------------------------------------------------
+/// Drives `device.poll(Maintain::Poll)` on a dedicated background thread so
+/// segment processing never blocks on `Maintain::Wait`: a `map_async`
+/// callback fires as soon as the poller notices the submission has landed,
+/// while the caller that's awaiting it (e.g. `download_buffer`) is free to
+/// have already queued the next segment's upload and dispatch in the
+/// meantime instead of sitting idle until the GPU replies. wasm32 has
+/// neither threads nor a blocking wait to avoid in the first place — the
+/// browser's own event loop drives WebGPU callbacks there, so this type
+/// doesn't exist on that target.
+#[cfg(not(target_arch = "wasm32"))]
+struct DevicePoller {
+    shutdown: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl DevicePoller {
+    fn spawn(device: wgpu::Device) -> Self {
+        let shutdown = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let shutdown_loop = shutdown.clone();
+        let handle = std::thread::Builder::new()
+            .name("gpu-device-poller".into())
+            .spawn(move || {
+                while !shutdown_loop.load(std::sync::atomic::Ordering::Relaxed) {
+                    device.poll(wgpu::Maintain::Poll);
+                    std::thread::sleep(std::time::Duration::from_micros(200));
+                }
+            })
+            .expect("failed to spawn GPU device poller thread");
+        Self { shutdown, handle: Some(handle) }
+    }
+}
+
+/// Signals the poller thread to stop and joins it, so dropping a
+/// `GpuContext` doesn't leak a thread still polling a device that's about
+/// to be torn down.
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for DevicePoller {
+    fn drop(&mut self) {
+        self.shutdown.store(true, std::sync::atomic::Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Recycles `wgpu::Buffer`s across segments, bucketed by (size, usage), so a
+/// long document processed segment-by-segment doesn't allocate and drop
+/// thousands of buffers in its hot loop. Modeled on burn-compute's
+/// `memory_management::simple`: a free list per size class, popped on
+/// `acquire` and refilled on `release`.
+/// Caps how many idle buffers a single (size, usage) class keeps around, so
+/// a long document doesn't grow the pool's resident GPU memory without
+/// bound — a handful covers the handful of distinct shapes `forward` cycles
+/// through per segment; anything beyond that is dropped (and its GPU
+/// allocation reclaimed by wgpu) rather than hoarded.
+const MAX_FREE_PER_CLASS: usize = 4;
+
+pub struct BufferPool {
+    free: HashMap<(u64, wgpu::BufferUsages), Vec<wgpu::Buffer>>,
+    /// Scratch zero-fill source for re-zeroing a reused buffer in `acquire`.
+    /// Grows to the largest size requested so far and is never shrunk, so a
+    /// document whose segments keep the same shape (the steady-state case)
+    /// allocates it once and then just re-slices it on every later reuse.
+    zero_scratch: Vec<u8>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self { free: HashMap::new(), zero_scratch: Vec::new() }
+    }
+
+    /// Returns a buffer of exactly `size` bytes with `usage`, reusing one
+    /// from the matching size class's free list when one is available. Only
+    /// re-zeroes a reused buffer when `needs_zero` is set — most callers
+    /// (e.g. a kernel output every element of which gets written this
+    /// segment) fully overwrite what they acquire, so the zero-fill would
+    /// just be redundant queue traffic; pass `true` only for a buffer a
+    /// kernel may leave partially untouched.
+    pub fn acquire(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        size: u64,
+        usage: wgpu::BufferUsages,
+        label: &str,
+        needs_zero: bool,
+    ) -> wgpu::Buffer {
+        if let Some(buffer) = self.free.get_mut(&(size, usage)).and_then(Vec::pop) {
+            if needs_zero && size > 0 {
+                if (self.zero_scratch.len() as u64) < size {
+                    self.zero_scratch.resize(size as usize, 0);
+                }
+                queue.write_buffer(&buffer, 0, &self.zero_scratch[..size as usize]);
+            }
+            return buffer;
+        }
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Returns `buffer` to its size-class free list for reuse by a later
+    /// `acquire`, unless that class is already at `MAX_FREE_PER_CLASS` — in
+    /// which case `buffer` is dropped instead of growing the list further.
+    /// The buffer must be unmapped before being released.
+    pub fn release(&mut self, buffer: wgpu::Buffer) {
+        let key = (buffer.size(), buffer.usage());
+        let list = self.free.entry(key).or_default();
+        if list.len() < MAX_FREE_PER_CLASS {
+            list.push(buffer);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Default chunk size for `StagingBelt`, large enough to cover a segment's
+/// worth of f32 embeddings at the demo's default dims without growing.
+const STAGING_BELT_CHUNK_SIZE: u64 = 1 << 16;
+
+/// CPU-write/GPU-read upload belt, after re_renderer's `CpuWriteGpuReadBelt`:
+/// owns a small set of persistently-mapped chunks so a caller can write
+/// embeddings directly into mapped memory instead of building a CPU `Vec`
+/// and uploading it via `create_buffer_init` every segment. `upload` hands
+/// out a write view backed by a free (or freshly created) chunk, copies it
+/// into the destination storage buffer, and recycles the chunk once that
+/// copy has completed so the next `upload` can reuse it.
+pub struct StagingBelt {
+    chunk_size: u64,
+    free: Vec<wgpu::Buffer>,
+}
+
+impl StagingBelt {
+    pub fn new(chunk_size: u64) -> Self {
+        Self { chunk_size, free: Vec::new() }
+    }
+
+    fn acquire_chunk(&mut self, device: &wgpu::Device, size: u64) -> wgpu::Buffer {
+        if let Some(pos) = self.free.iter().position(|b| b.size() >= size) {
+            return self.free.remove(pos);
+        }
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("staging_belt_chunk"),
+            size: size.max(self.chunk_size),
+            usage: wgpu::BufferUsages::MAP_WRITE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: true,
+        })
+    }
+
+    /// Writes `data` straight into a mapped chunk and records a single
+    /// `copy_buffer_to_buffer` into `dst`, then returns immediately — the
+    /// chunk's remap is kicked off in the background and the chunk rejoins
+    /// `free` from that callback once it lands. Callers don't block on (or
+    /// hold any lock across) the remap, so segment N's upload and N+1's can
+    /// genuinely overlap instead of serializing on this belt.
+    pub fn upload<T: bytemuck::Pod>(
+        &mut self,
+        context: &Arc<GpuContext>,
+        dst: &wgpu::Buffer,
+        data: &[T],
+    ) {
+        let size = (data.len() * std::mem::size_of::<T>()) as u64;
+        let chunk = self.acquire_chunk(&context.device, size);
+        {
+            let mut view = chunk.slice(0..size).get_mapped_range_mut();
+            view.copy_from_slice(bytemuck::cast_slice(data));
+        }
+        chunk.unmap();
+
+        let mut encoder = context.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Staging Belt Upload"),
+        });
+        encoder.copy_buffer_to_buffer(&chunk, 0, dst, 0, size);
+        context.queue.submit(Some(encoder.finish()));
+
+        // Two handles on the same chunk: `slice_chunk` only needs to live
+        // long enough to build the `BufferSlice` below, while `reclaim_chunk`
+        // is what the callback moves into `free` once the remap completes.
+        let chunk = Arc::new(chunk);
+        let slice_chunk = chunk.clone();
+        let reclaim_chunk = chunk;
+        let reclaim_context = context.clone();
+        slice_chunk.slice(..).map_async(wgpu::MapMode::Write, move |result| {
+            // Natively, `GpuContext`'s background `DevicePoller` thread
+            // drives this callback; under wasm32 the browser's WebGPU
+            // bridge drives it as part of its own event loop. Either way,
+            // by the time it fires `slice_chunk` above has already been
+            // dropped, so this is the sole remaining reference.
+            if result.is_ok() {
+                if let Ok(buffer) = Arc::try_unwrap(reclaim_chunk) {
+                    reclaim_context.staging_belt.lock().unwrap().free.push(buffer);
+                }
+            }
+        });
+    }
+}
 
 use bytemuck;
 
@@ -68,6 +293,129 @@ pub fn create_empty_storage_buffer<T: bytemuck::Pod>(
     })
 }
 
+/// Pooled equivalent of `create_storage_buffer`: acquires a buffer from
+/// `context.buffer_pool` (creating one on a cold size class) and uploads
+/// `data` into it via `queue.write_buffer`. Never needs a pool zero-fill —
+/// `data` covers the whole buffer a few lines down.
+pub fn acquire_storage_buffer<T: bytemuck::Pod>(
+    context: &GpuContext,
+    data: &[T],
+    label: &str,
+) -> wgpu::Buffer {
+    let size = (data.len() * std::mem::size_of::<T>()) as u64;
+    let usage = wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST;
+    let buffer = context
+        .buffer_pool
+        .lock()
+        .unwrap()
+        .acquire(&context.device, &context.queue, size, usage, label, false);
+    context.queue.write_buffer(&buffer, 0, bytemuck::cast_slice(data));
+    buffer
+}
+
+/// Pooled equivalent of `create_empty_storage_buffer`. Set `needs_zero` when
+/// the caller's kernel(s) may leave part of the buffer untouched; a buffer
+/// every element of which gets overwritten this segment doesn't need it.
+pub fn acquire_empty_storage_buffer<T: bytemuck::Pod>(
+    context: &GpuContext,
+    len: usize,
+    label: &str,
+    needs_zero: bool,
+) -> wgpu::Buffer {
+    let size = (len * std::mem::size_of::<T>()) as u64;
+    let usage = wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST;
+    context
+        .buffer_pool
+        .lock()
+        .unwrap()
+        .acquire(&context.device, &context.queue, size, usage, label, needs_zero)
+}
+
+/// Returns a buffer acquired via `acquire_storage_buffer`/`acquire_empty_storage_buffer`
+/// to the pool once the caller is done with it for this segment.
+pub fn release_buffer(context: &GpuContext, buffer: wgpu::Buffer) {
+    context.buffer_pool.lock().unwrap().release(buffer);
+}
+
+/// Runs a single compute kernel to completion: looks up (or builds and
+/// caches) its `ShaderModule`/`ComputePipeline`, binds `uniform_data` at
+/// binding 0 and `buffers` at bindings 1.., and submits one dispatch. The
+/// bind group layout is inferred from the shader itself, so callers never
+/// hand-declare `BindGroupLayoutEntry`s.
+///
+/// `shader_src`/`entry_point` must be `&'static str` — every caller passes a
+/// module-level `const` WGSL source, so the pipeline cache can key on the
+/// source's pointer instead of hashing the (often sizeable) shader text on
+/// every call.
+pub fn dispatch_compute(
+    context: &GpuContext,
+    shader_src: &'static str,
+    entry_point: &'static str,
+    uniform_data: &[u8],
+    buffers: &[&wgpu::Buffer],
+    workgroups: (u32, u32, u32),
+) {
+    let key = (shader_src.as_ptr() as usize, entry_point);
+    let pipeline = context
+        .pipeline_cache
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| {
+            let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(entry_point),
+                source: wgpu::ShaderSource::Wgsl(shader_src.into()),
+            });
+            Arc::new(context.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            }))
+        })
+        .clone();
+
+    let uniform_buf = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("uniform"),
+        contents: uniform_data,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+
+    let mut entries = vec![wgpu::BindGroupEntry {
+        binding: 0,
+        resource: uniform_buf.as_entire_binding(),
+    }];
+    for (i, buffer) in buffers.iter().enumerate() {
+        entries.push(wgpu::BindGroupEntry {
+            binding: (i + 1) as u32,
+            resource: buffer.as_entire_binding(),
+        });
+    }
+
+    let bind_group_layout = pipeline.get_bind_group_layout(0);
+    let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some(entry_point),
+        layout: &bind_group_layout,
+        entries: &entries,
+    });
+
+    let mut encoder = context.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Compute Encoder"),
+    });
+    {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(entry_point),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(workgroups.0, workgroups.1, workgroups.2);
+    }
+    context.queue.submit(Some(encoder.finish()));
+}
+
 pub async fn download_buffer<T: bytemuck::Pod>(
     context: &GpuContext,
     buffer: &wgpu::Buffer,
@@ -77,12 +425,15 @@ pub async fn download_buffer<T: bytemuck::Pod>(
     let mut encoder = context.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
         label: Some("Download Encoder"),
     });
-    let staging = context.device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Staging Buffer"),
+    let staging_usage = wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST;
+    let staging = context.buffer_pool.lock().unwrap().acquire(
+        &context.device,
+        &context.queue,
         size,
-        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
+        staging_usage,
+        "Staging Buffer",
+        false,
+    );
 
     encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
     context.queue.submit(Some(encoder.finish()));
@@ -90,10 +441,17 @@ pub async fn download_buffer<T: bytemuck::Pod>(
     let slice = staging.slice(..);
     let (tx, rx) = futures_intrusive::channel::shared::oneshot_channel();
     slice.map_async(wgpu::MapMode::Read, move |v| tx.send(v).unwrap());
-    context.device.poll(wgpu::Maintain::Wait);
+    // Natively, `GpuContext`'s background `DevicePoller` thread drives this
+    // callback, so this readback doesn't serialize the segment loop behind
+    // a blocking `Maintain::Wait`; under wasm32 the browser's WebGPU bridge
+    // resolves it as part of its own event loop. Either way, just await it.
     rx.receive().await;
 
-    let data = slice.get_mapped_range();
-    let result = bytemuck::cast_slice(&data).to_vec();
+    let result = {
+        let data = slice.get_mapped_range();
+        bytemuck::cast_slice(&data).to_vec()
+    };
+    staging.unmap();
+    context.buffer_pool.lock().unwrap().release(staging);
     Ok(result)
 }
\ No newline at end of file
@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Context, Result};
+use ndarray::Array2;
+use protobuf::Message;
+use std::path::Path;
+use wonnx::onnx::{ModelProto, TensorProto};
+
+/// Weights pulled out of an ONNX graph's initializers: the token-embedding
+/// table and the Q/K/V projection matrices, used in place of the demo's
+/// random embedding table and implicit identity QKV split.
+pub struct ModelWeights {
+    pub embedding_table: Array2<f32>,
+    pub w_q: Array2<f32>,
+    pub w_k: Array2<f32>,
+    pub w_v: Array2<f32>,
+}
+
+// ONNX exporters don't agree on a naming convention for these tensors, so we
+// match loosely against whichever of these fragments shows up in the name.
+const EMBEDDING_NAME_HINTS: &[&str] = &["embedding", "wte", "tok_embeddings"];
+const Q_NAME_HINTS: &[&str] = &["q_proj", "query", "attn.q", "wq"];
+const K_NAME_HINTS: &[&str] = &["k_proj", "key", "attn.k", "wk"];
+const V_NAME_HINTS: &[&str] = &["v_proj", "value", "attn.v", "wv"];
+
+/// Parses `path` as an ONNX model and pulls out the token-embedding matrix
+/// and the Q/K/V projection weights from its initializer tensors.
+pub fn load_model_weights(path: &Path) -> Result<ModelWeights> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("reading ONNX model {}", path.display()))?;
+    let model = ModelProto::parse_from_bytes(&bytes)
+        .with_context(|| format!("parsing ONNX model {}", path.display()))?;
+    let graph = model
+        .graph
+        .into_option()
+        .ok_or_else(|| anyhow!("ONNX model {} has no graph", path.display()))?;
+
+    Ok(ModelWeights {
+        embedding_table: find_tensor_2d(&graph.initializer, EMBEDDING_NAME_HINTS)?,
+        w_q: find_tensor_2d(&graph.initializer, Q_NAME_HINTS)?,
+        w_k: find_tensor_2d(&graph.initializer, K_NAME_HINTS)?,
+        w_v: find_tensor_2d(&graph.initializer, V_NAME_HINTS)?,
+    })
+}
+
+fn find_tensor_2d(initializers: &[TensorProto], hints: &[&str]) -> Result<Array2<f32>> {
+    let tensor = initializers
+        .iter()
+        .find(|t| {
+            let name = t.name.to_lowercase();
+            hints.iter().any(|hint| name.contains(hint))
+        })
+        .ok_or_else(|| anyhow!("no initializer tensor matching {:?}", hints))?;
+    tensor_to_array2(tensor)
+}
+
+fn tensor_to_array2(tensor: &TensorProto) -> Result<Array2<f32>> {
+    let dims: Vec<usize> = tensor.dims.iter().map(|&d| d as usize).collect();
+    let (rows, cols) = match dims[..] {
+        [rows, cols] => (rows, cols),
+        _ => return Err(anyhow!("expected a 2-D tensor for '{}', got shape {:?}", tensor.name, dims)),
+    };
+
+    let floats: Vec<f32> = if !tensor.raw_data.is_empty() {
+        tensor
+            .raw_data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    } else {
+        tensor.float_data.clone()
+    };
+
+    Array2::from_shape_vec((rows, cols), floats)
+        .with_context(|| format!("tensor '{}' data doesn't match its declared shape {:?}", tensor.name, dims))
+}